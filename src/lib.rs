@@ -1,9 +1,14 @@
 extern crate serde;
 extern crate serde_json;
-extern crate dice_roller;
+extern crate rand;
 
+mod rng;
+
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 
+pub use rng::Pcg32;
+
 
 /* Rolls
 ------------------------------------------------------------------------------------------- */
@@ -17,6 +22,120 @@ pub enum RollKind {
     Cancelled,
 }
 
+// A single term of a dice formula, either a flat number or `NdM` dice
+#[derive(Debug, Clone, Copy)]
+enum Term {
+    Const(i64),
+    Dice { count: i64, sides: i64 },
+}
+
+impl Term {
+
+    // Parse a single term such as "3", "d20" or "2d6"
+    fn parse(token: &str) -> Term {
+        match token.find(|c: char| c.eq_ignore_ascii_case(&'d')) {
+            Some(pos) => {
+                let count = &token[..pos];
+                let count = if count.is_empty() { 1 } else {
+                    count.parse().expect("Invalid dice count in formula.")
+                };
+                let sides = token[pos + 1..].parse()
+                    .expect("Invalid dice sides in formula.");
+                Term::Dice { count, sides }
+            }
+            None => Term::Const(token.parse().expect("Invalid term in formula.")),
+        }
+    }
+
+    // Roll this term using the given RNG
+    fn roll(&self, rng: &mut impl Rng) -> i64 {
+        match self {
+            Term::Const(n) => *n,
+            Term::Dice { count, sides } => (0..*count).map(|_| rng.gen_range(1..=*sides)).sum(),
+        }
+    }
+
+    // Double the number of dice rolled, used for critical hits
+    fn doubled(&self) -> Term {
+        match self {
+            Term::Dice { count, sides } => Term::Dice { count: count * 2, sides: *sides },
+            Term::Const(n) => Term::Const(*n),
+        }
+    }
+
+}
+
+// Split a formula into signed terms, e.g. "2d6+3-1" -> [(+,"2d6"), (+,"3"), (-,"1")]
+fn parse_formula(formula: &str) -> Vec<(i64, Term)> {
+    let mut terms = Vec::new();
+    let mut sign = 1;
+    let mut token = String::new();
+    for c in formula.chars() {
+        if c == '+' || c == '-' {
+            if !token.is_empty() {
+                terms.push((sign, Term::parse(&token)));
+                token.clear();
+            }
+            sign = if c == '-' { -1 } else { 1 };
+        } else if !c.is_whitespace() {
+            token.push(c);
+        }
+    }
+    if !token.is_empty() {
+        terms.push((sign, Term::parse(&token)));
+    }
+    terms
+}
+
+// Roll every term and sum the signed totals
+fn evaluate(terms: &[(i64, Term)], rng: &mut impl Rng) -> i64 {
+    terms.iter().map(|(sign, term)| sign * term.roll(rng)).sum()
+}
+
+// Roll every term, also reporting whether the first term, when it is a single
+// die, rolled its maximum ("natural") value -- used to detect a critical hit
+// on an attack roll. A die that is not the first term is never checked.
+fn evaluate_nat(terms: &[(i64, Term)], rng: &mut impl Rng) -> (i64, bool) {
+    let mut total = 0;
+    let mut nat_max = false;
+    for (i, (sign, term)) in terms.iter().enumerate() {
+        let value = term.roll(rng);
+        if i == 0 {
+            if let Term::Dice { count: 1, sides } = term {
+                nat_max = value == *sides;
+            }
+        }
+        total += sign * value;
+    }
+    (total, nat_max)
+}
+
+// Substitute `@name` tokens in a formula with values from a variable table,
+// e.g. "d20+@dex+@prof" with {dex: 3, prof: 2} becomes "d20+3+2"
+fn resolve_vars(formula: &str, vars: &std::collections::HashMap<String, i64>) -> String {
+    let mut resolved = String::new();
+    let mut chars = formula.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '@' {
+            let mut name = String::new();
+            while let Some(&next) = chars.peek() {
+                if next.is_alphanumeric() || next == '_' {
+                    name.push(next);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            let value = vars.get(&name)
+                .unwrap_or_else(|| panic!("Unknown variable '@{}' in formula.", name));
+            resolved.push_str(&value.to_string());
+        } else {
+            resolved.push(c);
+        }
+    }
+    resolved
+}
+
 // Encapsulate a dice roll formula
 // Takes advantage / disadvantage into consideration
 #[derive(Serialize, Deserialize, Debug)]
@@ -45,26 +164,98 @@ impl Roll {
         self
     }
 
-    // Execute the dice roll
-    pub fn roll(&self) -> i64 {
-        if !self.formula.contains("d") {
-            self.formula.parse()
-                .expect("Invalid format for formula.")
-        } else {
-            let dr = dice_roller::dice::Roller::parse(&self.formula);
-            match self.kind {
-                RollKind::Advantage    =>
-                    std::cmp::max(dr.roll().total(),  dr.roll().total()),
-                RollKind::Disadvantage =>
-                    std::cmp::min(dr.roll().total(), dr.roll().total()),
-                _                      =>
-                    dr.roll().total()
+    // Execute the dice roll, drawing randomness from the given RNG
+    pub fn roll(&self, rng: &mut impl Rng) -> i64 {
+        let terms = parse_formula(&self.formula);
+        self.apply_kind(&terms, rng)
+    }
+
+    // Execute the dice roll, resolving `@name` variables from the given table first
+    pub fn roll_with(&self, vars: &std::collections::HashMap<String, i64>, rng: &mut impl Rng) -> i64 {
+        let formula = resolve_vars(&self.formula, vars);
+        let terms = parse_formula(&formula);
+        self.apply_kind(&terms, rng)
+    }
+
+    // Roll with variables resolved, doubling every dice term's count for a critical hit
+    pub fn roll_crit_with(&self, vars: &std::collections::HashMap<String, i64>, rng: &mut impl Rng) -> i64 {
+        let formula = resolve_vars(&self.formula, vars);
+        let terms: Vec<(i64, Term)> = parse_formula(&formula).into_iter()
+            .map(|(sign, term)| (sign, term.doubled()))
+            .collect();
+        self.apply_kind(&terms, rng)
+    }
+
+    // Roll with variables resolved, also reporting whether the first term, when
+    // it is a single die, landed a natural maximum (e.g. a natural 20 on "d20+5")
+    pub fn roll_nat_with(&self, vars: &std::collections::HashMap<String, i64>, rng: &mut impl Rng) -> (i64, bool) {
+        let formula = resolve_vars(&self.formula, vars);
+        let terms = parse_formula(&formula);
+        match self.kind {
+            RollKind::Advantage => {
+                let (a, an) = evaluate_nat(&terms, rng);
+                let (b, bn) = evaluate_nat(&terms, rng);
+                if a >= b { (a, an) } else { (b, bn) }
             }
+            RollKind::Disadvantage => {
+                let (a, an) = evaluate_nat(&terms, rng);
+                let (b, bn) = evaluate_nat(&terms, rng);
+                if a <= b { (a, an) } else { (b, bn) }
+            }
+            _ => evaluate_nat(&terms, rng),
+        }
+    }
+
+    // Apply advantage/disadvantage (or neither) over a parsed set of terms
+    fn apply_kind(&self, terms: &[(i64, Term)], rng: &mut impl Rng) -> i64 {
+        match self.kind {
+            RollKind::Advantage    =>
+                std::cmp::max(evaluate(terms, rng), evaluate(terms, rng)),
+            RollKind::Disadvantage =>
+                std::cmp::min(evaluate(terms, rng), evaluate(terms, rng)),
+            _                      =>
+                evaluate(terms, rng),
         }
     }
 
     // Use this roll to make a check
-    pub fn check(&self, dc: i64) -> bool { self.roll() >= dc }
+    pub fn check(&self, dc: i64, rng: &mut impl Rng) -> bool { self.roll(rng) >= dc }
+
+}
+
+
+/* Attacks
+------------------------------------------------------------------------------------------- */
+
+// A reusable attack/weapon definition, loaded from a JSON asset
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Attack {
+    pub name: String,
+    pub attack: String,
+    pub damage: String,
+    pub damage_type: Option<String>,
+}
+
+impl Attack {
+
+    // Load every `*.json` attack definition from a directory, keyed by name
+    pub fn load_dir(dir: &str) -> std::collections::HashMap<String, Attack> {
+        let mut attacks = std::collections::HashMap::new();
+        if let Ok(entries) = std::fs::read_dir(dir) {
+            for entry in entries.filter_map(Result::ok) {
+                let path = entry.path();
+                if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                    continue;
+                }
+                match std::fs::read_to_string(&path).map(|data| serde_json::from_str::<Attack>(&data)) {
+                    Ok(Ok(attack)) => { attacks.insert(attack.name.clone(), attack); }
+                    Ok(Err(e)) => eprintln!("Could not parse attack file {}: {}", path.display(), e),
+                    Err(e) => eprintln!("Could not read attack file {}: {}", path.display(), e),
+                }
+            }
+        }
+        attacks
+    }
 
 }
 
@@ -87,14 +278,14 @@ pub struct HitPoints {
 
 impl HitPoints {
 
-    pub fn from(formula: &str) -> HitPoints {
-        let total = Roll::from(formula).roll();
+    pub fn from(formula: &str, rng: &mut impl Rng) -> HitPoints {
+        let total = Roll::from(formula).roll(rng);
         HitPoints { max: total, current: total, temp: 0 }
     }
 
     // Change the max hitpoints
-    pub fn set_max(&mut self, formula: &str) {
-        let total = Roll::from(formula).roll();
+    pub fn set_max(&mut self, formula: &str, rng: &mut impl Rng) {
+        let total = Roll::from(formula).roll(rng);
         self.current = self.current + (total - self.max);
         self.max = total;
     }
@@ -109,9 +300,9 @@ impl HitPoints {
         if hp > self.temp { self.temp = hp; }
     }
 
-    // Deal damage
-    pub fn deal(&mut self, dmg: i64) {
-        let mut _dmg = dmg;
+    // Deal damage of an optional type, applying the target's defenses first
+    pub fn deal(&mut self, dmg: i64, dmg_type: Option<&str>, defenses: &Defenses) {
+        let mut _dmg = defenses.modify(dmg, dmg_type);
         if self.temp > 0 {
             self.temp = std::cmp::max(self.temp - _dmg, 0);
             _dmg = _dmg - self.temp;
@@ -135,12 +326,76 @@ impl HitPoints {
 }
 
 
+/* Defenses
+------------------------------------------------------------------------------------------- */
+
+// A character's resistances, immunities and vulnerabilities to damage types
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Defenses {
+    pub resistances: std::collections::HashSet<String>,
+    pub immunities: std::collections::HashSet<String>,
+    pub vulnerabilities: std::collections::HashSet<String>,
+}
+
+impl Defenses {
+
+    // Apply the classic tabletop modifier for an incoming damage type:
+    // immune = x0, resistant = half (rounded down), vulnerable = double
+    pub fn modify(&self, dmg: i64, dmg_type: Option<&str>) -> i64 {
+        match dmg_type {
+            Some(t) if self.immunities.contains(t) => 0,
+            Some(t) if self.resistances.contains(t) => dmg / 2,
+            Some(t) if self.vulnerabilities.contains(t) => dmg * 2,
+            _ => dmg,
+        }
+    }
+
+}
+
+
+/* Effects
+------------------------------------------------------------------------------------------- */
+
+// The battle event that fires a status effect
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum EffectTrigger {
+    StartOfTurn,
+    EndOfTurn,
+    OnDamageTaken,
+}
+
+// What a status effect's formula is applied to
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum EffectTarget {
+    Heal,
+    Deal,
+    // Roll the formula as a save against a DC of max(10, dmg / 2); drop the
+    // effect on failure. Only meaningful on an OnDamageTaken trigger.
+    Concentration,
+}
+
+// A condition living on a character: regeneration, ongoing poison,
+// concentration, and similar event-hook status effects
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Effect {
+    pub name: String,
+    pub trigger: EffectTrigger,
+    pub target: EffectTarget,
+    pub formula: String,
+    pub damage_type: Option<String>,
+    pub duration: Option<i64>,
+}
+
+
 // Represent a single PC or NPC
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Character {
     name: String,
     pub init: Roll,
     pub hp: HitPoints,
+    pub defenses: Defenses,
+    pub variables: std::collections::HashMap<String, i64>,
+    pub effects: Vec<Effect>,
 }
 
 impl Character {
@@ -166,14 +421,49 @@ impl Character {
 /* Roster
 ------------------------------------------------------------------------------------------- */
 
+// Name of the pseudo-combatant representing lair actions at initiative 20
+pub const LAIR_ACTIONS: &str = "LAIR ACTIONS";
+
+// Persistent initiative order, current turn index and round counter
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Tracker {
+    order: Vec<(i64, String)>,
+    current: usize,
+    round: i64,
+}
+
 // A list of characters that roll initiative together
 #[derive(Serialize, Deserialize, Debug, Default)]
 pub struct Roster {
     chars: std::collections::HashMap<String, Character>,
+    rng: Pcg32,
+    tracker: Option<Tracker>,
 }
 
 impl Roster {
 
+    // Seed this roster's RNG, restarting the random sequence from scratch.
+    // This is what makes an encounter reproducible across invocations.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Pcg32::seed(seed);
+    }
+
+    // Roll a formula using this roster's RNG
+    pub fn roll(&mut self, roll: &Roll) -> i64 {
+        roll.roll(&mut self.rng)
+    }
+
+    // Roll a raw formula string using this roster's RNG
+    pub fn roll_formula(&mut self, formula: &str) -> i64 {
+        self.roll(&Roll::from(formula))
+    }
+
+    // Set a character's max HP from a formula, rolled with this roster's RNG
+    pub fn set_hp(&mut self, name: &str, formula: &str) {
+        let ch = self.chars.get_mut(name).expect("No character found with that name.");
+        ch.hp.set_max(formula, &mut self.rng);
+    }
+
     // Load a new roster from a file
     // If the file cannot be loaded, return an empty roster
     pub fn load_from(file: &str) -> Roster {
@@ -197,6 +487,9 @@ impl Roster {
         self.chars.insert(name.to_string(), Character {
             name: name.to_string(),
             hp: HitPoints::default(),
+            defenses: Defenses::default(),
+            variables: std::collections::HashMap::new(),
+            effects: Vec::new(),
             init,
         });
     }
@@ -221,15 +514,223 @@ impl Roster {
         self.chars.get_mut(name).expect("No character found with that name.")
     }
 
-    // Roll initiative for every character in the roster
-    pub fn roll_inits(&self) -> Vec<(i64, String)> {
+    // Roll initiative for every character in the roster, using their own variables.
+    // Characters are visited in sorted-name order so that, for a given seed, the
+    // same roll always lands on the same character -- `self.chars` is a HashMap
+    // whose iteration order is randomized per-process and cannot be relied on.
+    pub fn roll_inits(&mut self) -> Vec<(i64, String)> {
+        let mut names: Vec<&String> = self.chars.keys().collect();
+        names.sort();
         let mut inits = Vec::new();
-        for (name, character) in self.chars.iter() {
-            inits.push((character.init.roll(), name.clone()));
+        for name in names {
+            let character = &self.chars[name];
+            inits.push((character.init.roll_with(&character.variables, &mut self.rng), name.clone()));
         }
         inits
     }
 
+    // Roll a formula with a character's named variables available as `@name`
+    pub fn roll_for(&mut self, name: &str, formula: &str) -> i64 {
+        let vars = self.chars.get(name)
+            .expect("No character found with that name.")
+            .variables.clone();
+        Roll::from(formula).roll_with(&vars, &mut self.rng)
+    }
+
+    // Resolve an attack against a target's AC, dealing damage on a hit.
+    // Returns (attack roll, hit, damage rolled, effect messages); the damage is the
+    // rolled value before the target's defenses are applied, and 0 on a miss.
+    pub fn attack(&mut self, attacker: &str, atk: &Attack, target: &str, ac: i64) -> (i64, bool, i64, Vec<String>) {
+        let vars = self.chars.get(attacker)
+            .expect("No character found with that name.")
+            .variables.clone();
+        let (attack_roll, crit) = Roll::from(&atk.attack).roll_nat_with(&vars, &mut self.rng);
+        let hit = attack_roll >= ac;
+        if !hit {
+            return (attack_roll, false, 0, Vec::new());
+        }
+        let damage = Roll::from(&atk.damage);
+        let dmg = if crit {
+            damage.roll_crit_with(&vars, &mut self.rng)
+        } else {
+            damage.roll_with(&vars, &mut self.rng)
+        };
+        let messages = self.deal_damage(target, dmg, atk.damage_type.as_deref());
+        (attack_roll, true, dmg, messages)
+    }
+
+    // Deal damage to a character, then fire any on-damage-taken effects it
+    // carries (e.g. a concentration check that drops on failure).
+    // Returns log messages produced by those effects.
+    pub fn deal_damage(&mut self, name: &str, dmg: i64, dmg_type: Option<&str>) -> Vec<String> {
+        let ch = self.chars.get_mut(name).expect("No character found with that name.");
+        ch.hp.deal(dmg, dmg_type, &ch.defenses);
+        self.fire_damage_effects(name, dmg)
+    }
+
+    // Apply a single effect's heal/deal action to a character, returning a log message
+    fn apply_effect_action(&mut self, name: &str, vars: &std::collections::HashMap<String, i64>, effect: &Effect) -> String {
+        let amount = Roll::from(&effect.formula).roll_with(vars, &mut self.rng);
+        match effect.target {
+            EffectTarget::Heal => {
+                self.chars.get_mut(name).unwrap().hp.heal(amount);
+                format!("{} regains {} HP from {}.", name, amount, effect.name)
+            }
+            EffectTarget::Deal => {
+                let ch = self.chars.get_mut(name).unwrap();
+                ch.hp.deal(amount, effect.damage_type.as_deref(), &ch.defenses);
+                format!("{} takes {} damage from {}.", name, amount, effect.name)
+            }
+            EffectTarget::Concentration =>
+                unreachable!("concentration effects are resolved in fire_damage_effects"),
+        }
+    }
+
+    // Fire a character's start/end-of-turn effects. On the start-of-turn call,
+    // every effect with a duration ticks down by one and expires at 0; the
+    // end-of-turn call leaves durations alone. Returns log messages produced.
+    fn fire_effects(&mut self, name: &str, trigger: EffectTrigger) -> Vec<String> {
+        let (vars, effects) = match self.chars.get(name) {
+            Some(ch) => (ch.variables.clone(), ch.effects.clone()),
+            None => return Vec::new(),
+        };
+        let mut messages = Vec::new();
+        let mut remaining = Vec::new();
+        for mut effect in effects {
+            if effect.trigger == trigger && effect.target != EffectTarget::Concentration {
+                messages.push(self.apply_effect_action(name, &vars, &effect));
+            }
+            if trigger == EffectTrigger::StartOfTurn {
+                if let Some(duration) = effect.duration.as_mut() {
+                    *duration -= 1;
+                    if *duration <= 0 {
+                        messages.push(format!("{}'s {} expires.", name, effect.name));
+                        continue;
+                    }
+                }
+            }
+            remaining.push(effect);
+        }
+        self.chars.get_mut(name).unwrap().effects = remaining;
+        messages
+    }
+
+    // Fire a character's on-damage-taken effects, resolving concentration
+    // checks against a DC of max(10, dmg / 2). Returns log messages.
+    fn fire_damage_effects(&mut self, name: &str, dmg: i64) -> Vec<String> {
+        let (vars, effects) = match self.chars.get(name) {
+            Some(ch) => (ch.variables.clone(), ch.effects.clone()),
+            None => return Vec::new(),
+        };
+        let mut messages = Vec::new();
+        let mut remaining = Vec::new();
+        for effect in effects {
+            if effect.trigger != EffectTrigger::OnDamageTaken {
+                remaining.push(effect);
+                continue;
+            }
+            if effect.target == EffectTarget::Concentration {
+                let dc = std::cmp::max(10, dmg / 2);
+                let roll = Roll::from(&effect.formula).roll_with(&vars, &mut self.rng);
+                if roll >= dc {
+                    remaining.push(effect);
+                } else {
+                    messages.push(format!("{} loses concentration on {} (rolled {} vs DC {}).",
+                        name, effect.name, roll, dc));
+                }
+            } else {
+                messages.push(self.apply_effect_action(name, &vars, &effect));
+                remaining.push(effect);
+            }
+        }
+        self.chars.get_mut(name).unwrap().effects = remaining;
+        messages
+    }
+
+    // Return true if the named combatant can still take a turn.
+    // Lair actions are always available; a missing character counts as dead.
+    fn is_alive(&self, name: &str) -> bool {
+        name == LAIR_ACTIONS || self.chars.get(name).is_some_and(|ch| !ch.dead())
+    }
+
+    // Roll initiative and start a persistent turn tracker for the encounter.
+    // Lair actions are inserted as a pseudo-combatant at initiative 20.
+    pub fn start_combat(&mut self, lair: bool) {
+        let mut inits = self.roll_inits();
+        if lair {
+            inits.push((20, LAIR_ACTIONS.to_string()));
+        }
+        inits.sort_by(|a, b| b.0.cmp(&a.0));
+        self.tracker = Some(Tracker { order: inits, current: 0, round: 1 });
+    }
+
+    // Advance to the next living combatant, wrapping around into a new round.
+    // Fires the outgoing combatant's end-of-turn effects and the incoming
+    // combatant's start-of-turn effects. Returns the new combatant's name
+    // plus any log messages those effects produced.
+    pub fn next_turn(&mut self) -> Option<(String, Vec<String>)> {
+        let tracker = self.tracker.as_ref()?;
+        let len = tracker.order.len();
+        let mut current = tracker.current;
+        let mut round = tracker.round;
+        let prev_name = self.current_turn().map(|(name, _)| name.to_string());
+        for _ in 0..len {
+            current += 1;
+            if current >= len {
+                current = 0;
+                round += 1;
+            }
+            let name = self.tracker.as_ref().unwrap().order[current].1.clone();
+            if self.is_alive(&name) {
+                let tracker = self.tracker.as_mut().unwrap();
+                tracker.current = current;
+                tracker.round = round;
+                let mut messages = Vec::new();
+                if let Some(prev) = &prev_name {
+                    messages.extend(self.fire_effects(prev, EffectTrigger::EndOfTurn));
+                }
+                messages.extend(self.fire_effects(&name, EffectTrigger::StartOfTurn));
+                return Some((name, messages));
+            }
+        }
+        None
+    }
+
+    // Go back to the previous living combatant, unwinding a round if needed
+    pub fn prev_turn(&mut self) -> Option<String> {
+        let tracker = self.tracker.as_ref()?;
+        let len = tracker.order.len();
+        let mut current = tracker.current;
+        let mut round = tracker.round;
+        for _ in 0..len {
+            if current == 0 {
+                current = len - 1;
+                round -= 1;
+            } else {
+                current -= 1;
+            }
+            let name = self.tracker.as_ref().unwrap().order[current].1.clone();
+            if self.is_alive(&name) {
+                let tracker = self.tracker.as_mut().unwrap();
+                tracker.current = current;
+                tracker.round = round;
+                return Some(name);
+            }
+        }
+        None
+    }
+
+    // Return the name of whoever's turn it is and the current round
+    pub fn current_turn(&self) -> Option<(&str, i64)> {
+        let tracker = self.tracker.as_ref()?;
+        tracker.order.get(tracker.current).map(|(_, name)| (name.as_str(), tracker.round))
+    }
+
+    // Return the full initiative order, as rolled when combat started
+    pub fn turn_order(&self) -> Option<&[(i64, String)]> {
+        self.tracker.as_ref().map(|t| t.order.as_slice())
+    }
+
     // Remove dead characters
     pub fn wipe(&mut self) {
         // Get the names of all dead characters
@@ -12,44 +12,84 @@ fn main() -> Result<(), Box<dyn Error>> {
         (author: "Rafael Guillen <arthexis@gmail.com>")
         (about: "D&D Combat tools")
         (@arg ROSTER: -r --roster +takes_value "Roster definition file.")
+        (@arg SEED: --seed +takes_value "Seed the RNG for reproducible rolls.")
+        (@arg ATTACKS: --attacks +takes_value "Directory of attack/weapon JSON definitions.")
         (@subcommand roll =>
             (about: "roll arbitrary formula")
             (@arg formula: "Formula to roll, example: d20+3"))
+        (@subcommand save =>
+            (about: "roll a saving throw or check, substituting a character's @variables")
+            (@arg NAME: +required "Character name.")
+            (@arg FORMULA: +required "Roll formula, example: d20+@dex+@prof")
+            (@arg DC: --dc +takes_value "Difficulty class to check the roll against."))
         (@subcommand init =>
-            (about: "roll initiative")
+            (about: "roll initiative and start the turn tracker")
             (@arg LAIR: -l --lair "Include lair actions at initiative 20."))
+        (@subcommand next =>
+            (about: "advance the turn tracker to the next combatant"))
+        (@subcommand prev =>
+            (about: "move the turn tracker back to the previous combatant"))
+        (@subcommand turn =>
+            (about: "show whose turn it is and the full initiative order"))
         (@subcommand join =>
             (about: "add a character to the roster")
             (@arg NAME: +required "Character name.")
             (@arg INIT: -i --init +takes_value "Set initiative formula.")
             (@arg ADV: -a --adv "Rolls initiative with advantage.")
             (@arg DIS: -d --dis "Rolls initiative with disadvantage.")
-            (@arg HP: -h --hp +takes_value "Max HP value or formula."))
+            (@arg HP: -h --hp +takes_value "Max HP value or formula.")
+            (@arg VAR: --var +takes_value +multiple "Set a named variable, e.g. dex=3 (usable in formulas as @dex).")
+            (@arg RESIST: --resist +takes_value +multiple "Damage type this character resists (half damage).")
+            (@arg IMMUNE: --immune +takes_value +multiple "Damage type this character is immune to (no damage).")
+            (@arg VULN: --vuln +takes_value +multiple "Damage type this character is vulnerable to (double damage).")
+            (@arg EFFECT: --effect +takes_value +multiple "Attach a status effect: name:trigger:target:formula[:type][:duration]. trigger = start|end|damage, target = heal|deal|conc."))
         (@subcommand kill =>
             (about: "remove a character from the roster")
             (@arg NAME: +required "Character name."))
         (@subcommand deal =>
             (about: "Deal damage to a character.")
             (@arg NAME: +required "Character name.")
-            (@arg DMG: +required "Amount of damage."))
+            (@arg DMG: +required "Amount of damage.")
+            (@arg TYPE: -t --type +takes_value "Damage type (e.g. fire, slashing, poison)."))
         (@subcommand heal =>
             (about: "Heal damage to a character.")
             (@arg NAME: +required "Character name.")
             (@arg DMG: +required "Amount of healing."))
+        (@subcommand attack =>
+            (about: "Attack a target with a weapon from the attack library.")
+            (@arg ATTACKER: +required "Attacking character name.")
+            (@arg WEAPON: +required "Attack/weapon name from the attack library.")
+            (@arg TARGET: +required "Target character name.")
+            (@arg DC: --dc +takes_value "Target's armor class (defaults to the target's @ac variable)."))
     ).get_matches();
 
     // Load the party data from file
     let roster_file = matches.value_of("ROSTER").unwrap_or("roster.json");
     let mut roster = combat::Roster::load_from(roster_file);
 
+    // Load the attack/weapon library
+    let attacks_dir = matches.value_of("ATTACKS").unwrap_or("attacks");
+    let attacks = combat::Attack::load_dir(attacks_dir);
+
+    // Reseed the RNG if requested, so the whole session replays deterministically
+    if let Some(seed) = matches.value_of("SEED") {
+        let seed: u64 = seed.parse().expect("Invalid seed value.");
+        roster.set_seed(seed);
+    }
+
     // Evaluate requested command
     match matches.subcommand() {
-        ("roll", Some(m)) => { sc::roll(m); }
-        ("init", Some(m)) => { sc::init(m, &roster); }
+        ("roll", Some(m)) => { sc::roll(m, &mut roster); }
+        ("save", Some(m)) => { sc::save(m, &mut roster); }
+        ("init", Some(m)) => { sc::init(m, &mut roster); }
+        ("next", Some(_)) => { sc::next(&mut roster); }
+        ("prev", Some(_)) => { sc::prev(&mut roster); }
+        ("turn", Some(_)) => { sc::turn(&roster); }
         ("join", Some(m)) => { sc::join(m, &mut roster); }
         ("kill", Some(m)) => { sc::kill(m, &mut roster); }
         ("deal", Some(m)) => { sc::deal(m, &mut roster); }
         ("heal", Some(m)) => { sc::heal(m, &mut roster); }
+        ("attack", Some(m)) => { sc::attack(m, &mut roster, &attacks); }
         _                 => { println!("Unrecognized command."); }
     }
 
@@ -63,34 +103,86 @@ fn main() -> Result<(), Box<dyn Error>> {
 // Subcommands
 pub mod sc {
     use clap::ArgMatches;
+    use std::collections::HashMap;
 
     // Sub-command: roll <formula>
     // Perform an arbitrary roll
-    pub fn roll(matches: &ArgMatches) {
+    pub fn roll(matches: &ArgMatches, roster: &mut combat::Roster) {
         if let Some(formula) = matches.value_of("formula") {
-            println!("Roll {} = {}", formula, combat::Roll::from(&formula).roll());
+            println!("Roll {} = {}", formula, roster.roll_formula(formula));
         } else {
             eprintln!("Missing or invalid formula.");
         }
     }
 
+    // Sub-command: save <name> <formula> [--dc <dc>]
+    // Roll a saving throw or check, resolving the character's @variables first
+    pub fn save(matches: &ArgMatches, roster: &mut combat::Roster) {
+        let name = matches.value_of("NAME").unwrap();
+        let formula = matches.value_of("FORMULA").unwrap();
+        let roll = roster.roll_for(name, formula);
+        match matches.value_of("DC") {
+            Some(dc) => {
+                let dc: i64 = dc.parse().expect("Invalid --dc value, expected an integer.");
+                let result = if roll >= dc { "SUCCESS" } else { "FAILURE" };
+                println!("{} rolls {} = {} vs DC {} -- {}.", name, formula, roll, dc, result);
+            }
+            None => println!("{} rolls {} = {}.", name, formula, roll),
+        }
+    }
+
     // Sub-command: init
-    // Roll initiative for the entire party and encounter
-    pub fn init(matches: &ArgMatches, roster: &combat::Roster) {
-        let mut inits = roster.roll_inits();
-        if inits.len() == 0 {
-            println!("Roster is empty.");
-            return
-        }
-        if matches.is_present("LAIR") {
-            inits.push((20, String::from("LAIR ACTIONS")));
-        }
-        inits.sort_by(|a, b| b.0.cmp(&a.0));
-        println!("Initiative rolls:");
-        for init in inits.iter() {
-            let head = format!("{}: {}", init.0, init.1);
-            let tail = roster.get(&init.1).status();
-            println!("{} {}", head, tail);
+    // Roll initiative, start the turn tracker, and show the order
+    pub fn init(matches: &ArgMatches, roster: &mut combat::Roster) {
+        roster.start_combat(matches.is_present("LAIR"));
+        print_order(roster);
+    }
+
+    // Sub-command: next
+    // Advance the turn tracker to the next living combatant
+    pub fn next(roster: &mut combat::Roster) {
+        match roster.next_turn() {
+            Some((name, messages)) => {
+                println!("Round {}: {}'s turn.", roster.current_turn().unwrap().1, name);
+                for message in messages { println!("{}", message); }
+            }
+            None => println!("No combat in progress, run 'init' first."),
+        }
+    }
+
+    // Sub-command: prev
+    // Move the turn tracker back to the previous living combatant
+    pub fn prev(roster: &mut combat::Roster) {
+        match roster.prev_turn() {
+            Some(name) => println!("Round {}: {}'s turn.", roster.current_turn().unwrap().1, name),
+            None => println!("No combat in progress, run 'init' first."),
+        }
+    }
+
+    // Sub-command: turn
+    // Show whose turn it is and the full initiative order
+    pub fn turn(roster: &combat::Roster) {
+        match roster.current_turn() {
+            Some((name, round)) => println!("Round {}: {}'s turn.", round, name),
+            None => { println!("No combat in progress, run 'init' first."); return; }
+        }
+        print_order(roster);
+    }
+
+    // Print the current initiative order with each combatant's HP status
+    fn print_order(roster: &combat::Roster) {
+        match roster.turn_order() {
+            Some(order) if !order.is_empty() => {
+                println!("Initiative order:");
+                for (value, name) in order {
+                    let head = format!("{}: {}", value, name);
+                    let tail = if name == combat::LAIR_ACTIONS { String::new() } else {
+                        roster.get(name).status()
+                    };
+                    println!("{} {}", head, tail);
+                }
+            }
+            _ => println!("Roster is empty."),
         }
     }
 
@@ -123,9 +215,70 @@ pub mod sc {
         // Asign a max HP if necessary
         if matches.is_present("HP") {
             let formula = matches.value_of("HP").unwrap();
+            roster.set_hp(name, formula);
+            println!("Set max HP to {} ({}).", formula, roster.get(name).hp.max());
+        }
+
+        // Set named variables (ability modifiers, proficiency bonus, ...) if provided
+        if let Some(vars) = matches.values_of("VAR") {
             let ch = roster.get_mut(name);
-            ch.hp.set_max(formula);
-            println!("Set max HP to {} ({}).", formula, ch.hp.max());
+            for pair in vars {
+                let mut parts = pair.splitn(2, '=');
+                let var_name = parts.next().expect("Invalid --var, expected name=value.");
+                let value: i64 = parts.next()
+                    .expect("Invalid --var, expected name=value.")
+                    .parse().expect("Invalid --var value, expected an integer.");
+                ch.variables.insert(var_name.to_string(), value);
+            }
+        }
+
+        // Declare damage-type defenses if provided
+        let ch = roster.get_mut(name);
+        if let Some(types) = matches.values_of("RESIST") {
+            ch.defenses.resistances.extend(types.map(String::from));
+        }
+        if let Some(types) = matches.values_of("IMMUNE") {
+            ch.defenses.immunities.extend(types.map(String::from));
+        }
+        if let Some(types) = matches.values_of("VULN") {
+            ch.defenses.vulnerabilities.extend(types.map(String::from));
+        }
+
+        // Attach status effects (poison, regeneration, concentration, ...) if provided
+        if let Some(specs) = matches.values_of("EFFECT") {
+            let ch = roster.get_mut(name);
+            for spec in specs {
+                ch.effects.push(parse_effect(spec));
+            }
+        }
+    }
+
+    // Parse a `name:trigger:target:formula[:type][:duration]` --effect spec
+    fn parse_effect(spec: &str) -> combat::Effect {
+        let parts: Vec<&str> = spec.splitn(6, ':').collect();
+        if parts.len() < 4 {
+            panic!("Invalid --effect '{}', expected name:trigger:target:formula[:type][:duration].", spec);
+        }
+        let trigger = match parts[1] {
+            "start" => combat::EffectTrigger::StartOfTurn,
+            "end" => combat::EffectTrigger::EndOfTurn,
+            "damage" => combat::EffectTrigger::OnDamageTaken,
+            other => panic!("Invalid --effect trigger '{}', expected start, end or damage.", other),
+        };
+        let target = match parts[2] {
+            "heal" => combat::EffectTarget::Heal,
+            "deal" => combat::EffectTarget::Deal,
+            "conc" | "concentration" => combat::EffectTarget::Concentration,
+            other => panic!("Invalid --effect target '{}', expected heal, deal or concentration.", other),
+        };
+        combat::Effect {
+            name: parts[0].to_string(),
+            trigger,
+            target,
+            formula: parts[3].to_string(),
+            damage_type: parts.get(4).filter(|s| !s.is_empty()).map(|s| s.to_string()),
+            duration: parts.get(5).filter(|s| !s.is_empty())
+                .map(|s| s.parse().expect("Invalid --effect duration, expected an integer.")),
         }
     }
 
@@ -143,19 +296,46 @@ pub mod sc {
     // Sub-command: deal <name> <dmg>
     pub fn deal(matches: &ArgMatches, roster: &mut combat::Roster) {
         let name = matches.value_of("NAME").unwrap();
-        let dmg = combat::Roll::from(matches.value_of("DMG").unwrap()).roll();
-        let ch = roster.get_mut(name);
-        ch.hp.deal(dmg);
-        println!("{} took {} damage, now has {} HP.", name, dmg, ch.hp.current());
+        let dmg = roster.roll_formula(matches.value_of("DMG").unwrap());
+        let dmg_type = matches.value_of("TYPE");
+        let messages = roster.deal_damage(name, dmg, dmg_type);
+        println!("{} took {} damage, now has {} HP.", name, dmg, roster.get(name).hp.current());
+        for message in messages { println!("{}", message); }
     }
 
     // Sub-command: heal <name> <dmg>
     pub fn heal(matches: &ArgMatches, roster: &mut combat::Roster) {
         let name = matches.value_of("NAME").unwrap();
-        let dmg = combat::Roll::from(matches.value_of("DMG").unwrap()).roll();
+        let dmg = roster.roll_formula(matches.value_of("DMG").unwrap());
         let ch = roster.get_mut(name);
         ch.hp.heal(dmg);
         println!("{} healed {} damage, now has {} HP.", name, dmg, ch.hp.current());
     }
 
+    // Sub-command: attack <attacker> <weapon> <target> [--dc <ac>]
+    pub fn attack(matches: &ArgMatches, roster: &mut combat::Roster, attacks: &HashMap<String, combat::Attack>) {
+        let attacker = matches.value_of("ATTACKER").unwrap();
+        let weapon = matches.value_of("WEAPON").unwrap();
+        let target = matches.value_of("TARGET").unwrap();
+
+        let atk = attacks.get(weapon)
+            .unwrap_or_else(|| panic!("No attack found named '{}'.", weapon));
+
+        let ac = match matches.value_of("DC") {
+            Some(dc) => dc.parse().expect("Invalid --dc value, expected an integer."),
+            None => *roster.get(target).variables.get("ac")
+                .unwrap_or_else(|| panic!(
+                    "Target '{}' has no @ac variable set, and no --dc was given.", target)),
+        };
+
+        let (roll, hit, dmg, messages) = roster.attack(attacker, atk, target, ac);
+        if hit {
+            println!("{} attacks {} with {}: {} vs AC {} -- HIT for {} damage, {} now has {} HP.",
+                attacker, target, weapon, roll, ac, dmg, target, roster.get(target).hp.current());
+        } else {
+            println!("{} attacks {} with {}: {} vs AC {} -- MISS.", attacker, target, weapon, roll, ac);
+        }
+        for message in messages { println!("{}", message); }
+    }
+
 }
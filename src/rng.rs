@@ -0,0 +1,68 @@
+// A small, self-contained, seedable RNG used as the single source of
+// randomness for a combat session. This replaces dice_roller's own internal
+// randomness so encounters can be seeded and replayed deterministically.
+//
+// Implements the public PCG32 algorithm (O'Neill, 2014): `state` advances by
+// one step per 32-bit output, two for a `next_u64` (the "stream position"), while `inc` is fixed
+// for the life of the generator and is derived from the seed. Both fields are
+// serialized so a roster can persist and resume the exact same sequence.
+
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+const MULTIPLIER: u64 = 6364136223846793005;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Pcg32 {
+    state: u64,
+    inc: u64,
+}
+
+impl Pcg32 {
+
+    // Create a new generator from a seed
+    pub fn seed(seed: u64) -> Pcg32 {
+        let mut rng = Pcg32 { state: 0, inc: (seed << 1) | 1 };
+        rng.step();
+        rng.state = rng.state.wrapping_add(seed);
+        rng.step();
+        rng
+    }
+
+    fn step(&mut self) {
+        self.state = self.state.wrapping_mul(MULTIPLIER).wrapping_add(self.inc);
+    }
+
+}
+
+impl Default for Pcg32 {
+    fn default() -> Pcg32 { Pcg32::seed(0) }
+}
+
+impl RngCore for Pcg32 {
+
+    fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.step();
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        let rot = (old >> 59) as u32;
+        xorshifted.rotate_right(rot)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        ((self.next_u32() as u64) << 32) | self.next_u32() as u64
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(4) {
+            let bytes = self.next_u32().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+
+}